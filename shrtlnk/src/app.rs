@@ -1,18 +1,37 @@
-use std::{convert::Infallible, fs::File, io::Read, sync::Arc};
+use std::{
+    convert::Infallible,
+    fs::File,
+    future::Future,
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context as PollContext, Poll},
+    time::Duration,
+};
 
-use crate::config::{CheckConfig, Config};
-use anyhow::{anyhow, Result};
+use crate::config::{CheckConfig, Config, StaticPage};
+use crate::tls::{CertManager, TlsHyperAcceptor, TlsSpec};
+use anyhow::{anyhow, Context, Result};
 use hyper::{
     body::{Body, Bytes},
-    server::conn::AddrStream,
+    server::{
+        accept::Accept,
+        conn::{AddrIncoming, AddrStream},
+    },
     service::{make_service_fn, service_fn},
     Request, Response, Server,
 };
-use tokio::sync::RwLock;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::RwLock,
+    time::{Instant, Sleep},
+};
 
 pub struct Application {
     config_location: String,
     config: RwLock<Option<Config>>,
+    cert_manager: RwLock<Option<Arc<CertManager>>>,
 }
 
 impl Application {
@@ -20,6 +39,7 @@ impl Application {
         let me = Self {
             config_location,
             config: RwLock::new(None),
+            cert_manager: RwLock::new(None),
         };
 
         if !me.config_location.is_empty() {
@@ -59,11 +79,99 @@ impl Application {
             task::spawn(Self::signal_monitor(me.clone()));
         }
 
+        if !me.config_location.is_empty() {
+            tokio::task::spawn(Self::config_watcher(me.clone()));
+        }
+
         Self::setup_server(me.clone()).await?;
 
         Ok(())
     }
 
+    async fn config_watcher(me: Arc<Self>) {
+        use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+        const DEBOUNCE: Duration = Duration::from_millis(300);
+
+        let config_path = std::path::Path::new(&me.config_location);
+        let (watch_dir, file_name) = match (config_path.parent(), config_path.file_name()) {
+            (Some(parent), Some(file_name)) => {
+                let parent = if parent.as_os_str().is_empty() {
+                    std::path::Path::new(".")
+                } else {
+                    parent
+                };
+                (parent, file_name.to_owned())
+            }
+            _ => {
+                eprintln!(
+                    "Could not determine a parent directory to watch for {}",
+                    me.config_location
+                );
+                return;
+            }
+        };
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+
+        let mut watcher = match RecommendedWatcher::new(
+            move |res| {
+                let _ = tx.blocking_send(res);
+            },
+            notify::Config::default(),
+        ) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                eprintln!("Failed to start config file watcher: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = watcher.watch(watch_dir, RecursiveMode::NonRecursive) {
+            eprintln!(
+                "Failed to watch directory {} for config changes: {}",
+                watch_dir.display(),
+                e
+            );
+            return;
+        }
+
+        while let Some(res) = rx.recv().await {
+            let event = match res {
+                Ok(event) => event,
+                Err(e) => {
+                    eprintln!("Config file watcher error: {}", e);
+                    continue;
+                }
+            };
+
+            let is_relevant = matches!(
+                event.kind,
+                EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_)
+            ) && event
+                .paths
+                .iter()
+                .any(|p| p.file_name() == Some(file_name.as_os_str()));
+
+            if !is_relevant {
+                continue;
+            }
+
+            // debounce: a single save can fire several events in quick succession
+            loop {
+                match tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+                    Ok(Some(_)) => continue,
+                    _ => break,
+                }
+            }
+
+            match me.reload_config().await {
+                Ok(_) => eprintln!("Successfully reloaded configuration"),
+                Err(e) => eprintln!("Got an error during configuration reload: {}", e),
+            }
+        }
+    }
+
     pub async fn reload_config(&self) -> Result<()> {
         let mut content = String::new();
         File::open(self.config_location.as_str())?.read_to_string(&mut content)?;
@@ -92,7 +200,34 @@ impl Application {
     }
 
     pub async fn setup_server(me: Arc<Self>) -> Result<()> {
-        let the_service = make_service_fn(|_: &AddrStream| {
+        let (listener, tls, timeouts) = {
+            if let Some(ref some_config) = *me.config.read().await {
+                (
+                    some_config.host.spec(),
+                    some_config.host.tls.clone(),
+                    some_config.timeouts.clone(),
+                )
+            } else {
+                return Err(anyhow!(
+                    "server was not configured before attempting to run."
+                ));
+            }
+        };
+
+        let acme_manager = if let Some(TlsSpec::Acme { .. }) = tls {
+            let manager = CertManager::new(tls.clone().unwrap());
+            *me.cert_manager.write().await = Some(manager.clone());
+
+            // Let's Encrypt validates HTTP-01 challenges over plain HTTP on port
+            // 80, regardless of whatever port/scheme we're actually serving on.
+            Self::spawn_acme_challenge_listener(listener.ip(), manager.clone())?;
+
+            Some(manager)
+        } else {
+            None
+        };
+
+        let the_service = make_service_fn(move |_: &AddrStream| {
             let me_clone = me.clone();
             async move {
                 Ok::<_, Infallible>(service_fn(move |r| {
@@ -102,24 +237,227 @@ impl Application {
             }
         });
 
-        let listener = {
-            if let Some(ref some_config) = *me.config.read().await {
-                some_config.host.spec()
-            } else {
-                return Err(anyhow!(
-                    "server was not configured before attempting to run."
-                ));
+        let incoming = AddrIncoming::bind(&listener)?;
+        // `keep_alive` is meant to disconnect clients that hold a connection
+        // open without sending anything; TCP-level SO_KEEPALIVE probing
+        // doesn't do that (it only detects a dead peer, on an hours-scale
+        // cadence), so enforce it as an idle-read deadline instead.
+        let incoming = IdleTimeoutAccept::new(incoming, timeouts.keep_alive.map(Duration::from_secs));
+
+        let header_read_timeout = timeouts.header_read.map(Duration::from_secs);
+
+        let result = match tls {
+            None => {
+                let mut builder = Server::builder(incoming);
+                if let Some(timeout) = header_read_timeout {
+                    builder = builder.http1_header_read_timeout(timeout);
+                }
+                builder.serve(the_service).await
+            }
+            Some(TlsSpec::Manual { cert_file, key_file }) => {
+                let server_config = Self::load_manual_tls(&cert_file, &key_file)?;
+                let acceptor = TlsHyperAcceptor::new(incoming, Arc::new(server_config));
+                let mut builder = Server::builder(acceptor);
+                if let Some(timeout) = header_read_timeout {
+                    builder = builder.http1_header_read_timeout(timeout);
+                }
+                builder.serve(the_service).await
+            }
+            Some(TlsSpec::Acme { ref domain, .. }) => {
+                let manager = acme_manager.expect("ACME manager was set up above");
+                let acceptor = TlsHyperAcceptor::new(incoming, Arc::new(manager.server_config()));
+                let mut builder = Server::builder(acceptor);
+                if let Some(timeout) = header_read_timeout {
+                    builder = builder.http1_header_read_timeout(timeout);
+                }
+
+                // must already be serving before the ACME server can validate us
+                let domain = domain.clone();
+                tokio::task::spawn(Self::provision_and_renew(manager, domain));
+
+                builder.serve(the_service).await
             }
         };
 
-        if let Err(e) = Server::bind(&listener).serve(the_service).await {
+        if let Err(e) = result {
             return Err(anyhow!(e));
         }
 
         Ok(())
     }
 
+    fn spawn_acme_challenge_listener(host: IpAddr, manager: Arc<CertManager>) -> Result<()> {
+        let incoming = AddrIncoming::bind(&SocketAddr::new(host, 80))
+            .context("binding the plain-HTTP ACME challenge listener on port 80")?;
+
+        let service = make_service_fn(move |_: &AddrStream| {
+            let manager = manager.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let manager = manager.clone();
+                    async move { Ok::<_, Infallible>(Self::acme_challenge_response(&manager, &req).await) }
+                }))
+            }
+        });
+
+        tokio::task::spawn(async move {
+            if let Err(e) = Server::builder(incoming).serve(service).await {
+                eprintln!("ACME challenge listener on port 80 failed: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn acme_challenge_response(manager: &CertManager, req: &Request<Body>) -> Response<Body> {
+        if let Some(token) = req.uri().path().strip_prefix("/.well-known/acme-challenge/") {
+            if let Some(key_auth) = manager.challenge_response(token).await {
+                return Response::builder()
+                    .status(200)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from(key_auth))
+                    .unwrap();
+            }
+        }
+
+        Response::builder().status(404).body(Body::empty()).unwrap()
+    }
+
+    async fn provision_and_renew(manager: Arc<CertManager>, domain: String) {
+        const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+        while let Err(e) = manager.sync_cert(&domain).await {
+            eprintln!(
+                "Failed to provision the initial ACME certificate for {}: {}. Retrying in {}s",
+                domain,
+                e,
+                RETRY_INTERVAL.as_secs()
+            );
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+
+        CertManager::spawn_renewal_timer(manager);
+    }
+
+    fn load_manual_tls(
+        cert_file: &str,
+        key_file: &str,
+    ) -> Result<tokio_rustls::rustls::ServerConfig> {
+        let mut cert_reader = std::io::BufReader::new(File::open(cert_file)?);
+        let certs = rustls_pemfile::certs(&mut cert_reader)?
+            .into_iter()
+            .map(tokio_rustls::rustls::Certificate)
+            .collect();
+
+        let mut key_reader = std::io::BufReader::new(File::open(key_file)?);
+        let key = rustls_pemfile::pkcs8_private_keys(&mut key_reader)?
+            .into_iter()
+            .next()
+            .map(tokio_rustls::rustls::PrivateKey)
+            .ok_or_else(|| anyhow!("no private key found in {}", key_file))?;
+
+        let mut server_config = tokio_rustls::rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(server_config)
+    }
+
     pub async fn handle_request(&self, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        let request_body_timeout = self
+            .config
+            .read()
+            .await
+            .as_ref()
+            .and_then(|c| c.timeouts.request_body)
+            .map(Duration::from_secs);
+
+        let request_body_timeout = match request_body_timeout {
+            Some(timeout) => timeout,
+            None => return self.handle_request_inner(req).await,
+        };
+
+        // ReverseProxy forwards the body to the upstream as a stream; buffering
+        // it up front here would hold large uploads entirely in memory. Instead,
+        // bound its reception with a timeout that aborts the stream, rather than
+        // eagerly consuming it.
+        if self.matched_handler_is_proxy(&req).await {
+            let (parts, body) = req.into_parts();
+            let body = Self::timeout_body(body, request_body_timeout);
+            return self.handle_request_inner(Request::from_parts(parts, body)).await;
+        }
+
+        let (parts, body) = req.into_parts();
+        let body = match tokio::time::timeout(request_body_timeout, hyper::body::to_bytes(body)).await {
+            Ok(body) => body?,
+            Err(_) => {
+                return Ok(Response::builder()
+                    .status(408)
+                    .header("Connection", "close")
+                    .body(Body::from(Bytes::from_static(b"408: request timeout")))?)
+            }
+        };
+
+        self.handle_request_inner(Request::from_parts(parts, Body::from(body)))
+            .await
+    }
+
+    async fn matched_handler_is_proxy(&self, req: &Request<Body>) -> bool {
+        matches!(
+            self.config
+                .read()
+                .await
+                .as_ref()
+                .and_then(|c| c.handlers.iter().find(|h| h.matcher.matches(req)))
+                .map(|h| &h.page),
+            Some(StaticPage::ReverseProxy { .. })
+        )
+    }
+
+    /// Re-streams `body` through a channel, aborting it if `timeout` elapses
+    /// between chunks, instead of buffering the whole thing into memory.
+    fn timeout_body(body: Body, timeout: Duration) -> Body {
+        use hyper::body::HttpBody;
+
+        let (mut sender, new_body) = Body::channel();
+        tokio::task::spawn(async move {
+            let mut body = body;
+            loop {
+                match tokio::time::timeout(timeout, body.data()).await {
+                    Ok(Some(Ok(chunk))) => {
+                        if sender.send_data(chunk).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(Some(Err(_))) | Ok(None) => break,
+                    Err(_) => {
+                        sender.abort();
+                        break;
+                    }
+                }
+            }
+        });
+        new_body
+    }
+
+    async fn handle_request_inner(&self, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        if let Some(token) = req
+            .uri()
+            .path()
+            .strip_prefix("/.well-known/acme-challenge/")
+        {
+            if let Some(ref manager) = *self.cert_manager.read().await {
+                if let Some(key_auth) = manager.challenge_response(token).await {
+                    return Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from(key_auth))?);
+                }
+            }
+        }
+
         let config = self.config.read().await;
         if let Some(ref config_struct) = *config {
             if let Some(handler) = config_struct
@@ -127,7 +465,8 @@ impl Application {
                 .iter()
                 .find(|m| m.matcher.matches(&req))
             {
-                handler.page.serve(req).await
+                let captures = handler.matcher.captures(&req);
+                handler.page.serve(req, captures.as_ref()).await
             } else {
                 Ok(Response::builder()
                     .status(404)
@@ -139,6 +478,103 @@ impl Application {
     }
 }
 
+/// Wraps an [`Accept`] so every accepted connection is disconnected if it
+/// goes `timeout` seconds without making any read progress. `timeout: None`
+/// makes this a no-op passthrough.
+struct IdleTimeoutAccept<A> {
+    inner: A,
+    timeout: Option<Duration>,
+}
+
+impl<A> IdleTimeoutAccept<A> {
+    fn new(inner: A, timeout: Option<Duration>) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<A: Accept + Unpin> Accept for IdleTimeoutAccept<A> {
+    type Conn = IdleTimeoutIo<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut PollContext<'_>,
+    ) -> Poll<Option<std::result::Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_accept(cx) {
+            Poll::Ready(Some(Ok(conn))) => {
+                Poll::Ready(Some(Ok(IdleTimeoutIo::new(conn, this.timeout))))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+struct IdleTimeoutIo<T> {
+    inner: T,
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> IdleTimeoutIo<T> {
+    fn new(inner: T, timeout: Option<Duration>) -> Self {
+        let sleep = timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout)));
+        Self {
+            inner,
+            timeout,
+            sleep,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeoutIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut PollContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let Some(sleep) = self.sleep.as_mut() {
+            if sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "connection idle timeout",
+                )));
+            }
+        }
+
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(timeout)) = (&result, self.timeout) {
+            if buf.filled().len() > before {
+                if let Some(sleep) = self.sleep.as_mut() {
+                    sleep.as_mut().reset(Instant::now() + timeout);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeoutIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut PollContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut PollContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut PollContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
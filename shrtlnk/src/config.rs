@@ -1,16 +1,36 @@
-use std::{borrow::Borrow, fs::File, io::Read, net::SocketAddr};
+use std::{borrow::Borrow, collections::HashMap, fs::File, io::Read, net::SocketAddr, time::SystemTime};
 
 use anyhow::{anyhow, Context};
 use hyper::{
     body::{Body, Bytes},
+    header::{IF_MODIFIED_SINCE, IF_NONE_MATCH},
     Request, Response, Uri,
 };
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::tls::TlsSpec;
 
 pub trait CheckConfig {
     fn check(&mut self) -> anyhow::Result<()>;
 }
 
+pub struct CaptureSet {
+    named: HashMap<String, String>,
+    numbered: Vec<String>,
+}
+
+impl CaptureSet {
+    fn get(&self, key: &str) -> Option<&str> {
+        if let Ok(index) = key.parse::<usize>() {
+            self.numbered.get(index).map(|s| s.as_str())
+        } else {
+            self.named.get(key).map(|s| s.as_str())
+        }
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(tag = "matches")]
 pub enum Matcher {
@@ -30,6 +50,18 @@ pub enum Matcher {
     Not { matcher: Box<Matcher> },
     #[serde(rename = "root")]
     Root,
+    #[serde(rename = "method")]
+    Method { methods: Vec<String> },
+    #[serde(rename = "header")]
+    Header {
+        name: String,
+        value: Option<String>,
+        regex: Option<String>,
+        #[serde(skip)]
+        compiled: Option<regex::Regex>,
+    },
+    #[serde(rename = "host")]
+    Host { host: String },
 }
 
 impl Matcher {
@@ -62,6 +94,102 @@ impl Matcher {
             }
             Self::Not { matcher } => !matcher.matches(req),
             Self::Root => req.uri().path().chars().all(|c| c == '/'),
+            Self::Method { methods } => methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(req.method().as_str())),
+            Self::Header {
+                name,
+                value,
+                compiled,
+                ..
+            } => match req.headers().get(name) {
+                Some(header_value) => {
+                    let header_value = match header_value.to_str() {
+                        Ok(s) => s,
+                        Err(_) => return false,
+                    };
+
+                    if let Some(compiled) = compiled {
+                        compiled.is_match(header_value)
+                    } else if let Some(value) = value {
+                        header_value == value.as_str()
+                    } else {
+                        true
+                    }
+                }
+                None => false,
+            },
+            Self::Host { host } => match Self::request_host(req) {
+                Some(req_host) => Self::strip_port(req_host).eq_ignore_ascii_case(host.as_str()),
+                None => false,
+            },
+        }
+    }
+
+    pub fn captures(&self, req: &Request<Body>) -> Option<CaptureSet> {
+        match self {
+            Self::Regex { compiled, .. } => {
+                let re = compiled.as_ref().unwrap();
+                let caps = re.captures(req.uri().path())?;
+
+                let numbered = (0..caps.len())
+                    .map(|i| caps.get(i).map(|m| m.as_str().to_string()).unwrap_or_default())
+                    .collect();
+                let named = re
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| caps.name(name).map(|m| (name.to_string(), m.as_str().to_string())))
+                    .collect();
+
+                Some(CaptureSet { named, numbered })
+            }
+            Self::All { of } | Self::Any { of } => of.iter().find_map(|m| m.captures(req)),
+            Self::Not { matcher } => matcher.captures(req),
+            Self::Path { .. }
+            | Self::Root
+            | Self::Method { .. }
+            | Self::Header { .. }
+            | Self::Host { .. } => None,
+        }
+    }
+
+    pub fn regex_group_info(&self) -> Option<(usize, Vec<String>)> {
+        match self {
+            Self::Regex { compiled, .. } => {
+                let re = compiled.as_ref()?;
+                let names = re.capture_names().flatten().map(|s| s.to_string()).collect();
+                Some((re.captures_len(), names))
+            }
+            Self::All { of } | Self::Any { of } => of.iter().find_map(|m| m.regex_group_info()),
+            Self::Not { matcher } => matcher.regex_group_info(),
+            Self::Path { .. }
+            | Self::Root
+            | Self::Method { .. }
+            | Self::Header { .. }
+            | Self::Host { .. } => None,
+        }
+    }
+
+    fn request_host(req: &Request<Body>) -> Option<&str> {
+        req.headers()
+            .get(hyper::header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().authority().map(|a| a.as_str()))
+    }
+
+    fn strip_port(host: &str) -> &str {
+        if host.starts_with('[') {
+            // IPv6 literal, e.g. "[::1]:8387" or "[::1]"; don't let the
+            // address's own colons be mistaken for a port separator.
+            return match host.find(']') {
+                Some(end) => &host[..=end],
+                None => host,
+            };
+        }
+
+        match host.rfind(':') {
+            Some(idx) => &host[..idx],
+            None => host,
         }
     }
 }
@@ -105,11 +233,20 @@ impl CheckConfig for Matcher {
             }
             Self::Not { matcher } => matcher.check(),
             Self::Root => Ok(()),
+            Self::Method { .. } => Ok(()),
+            Self::Header { regex, compiled, .. } => {
+                if let Some(expr) = regex {
+                    *compiled = Some(regex::Regex::new(expr.as_str())?);
+                }
+
+                Ok(())
+            }
+            Self::Host { .. } => Ok(()),
         }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum StaticPage {
     #[serde(rename = "redirect")]
@@ -120,6 +257,8 @@ pub enum StaticPage {
         data: Vec<u8>,
         #[serde(default = "StaticPage::default_content_type")]
         content_type: String,
+        #[serde(skip)]
+        cached_etag: String,
     },
     #[serde(rename = "file")]
     StaticFile {
@@ -128,6 +267,10 @@ pub enum StaticPage {
         content_type: String,
         #[serde(skip)]
         cached_data: Vec<u8>,
+        #[serde(skip)]
+        cached_etag: String,
+        #[serde(skip)]
+        last_modified: Option<SystemTime>,
     },
     #[serde(rename = "proxy")]
     ReverseProxy {
@@ -137,6 +280,14 @@ pub enum StaticPage {
         #[serde(skip)]
         client: hyper::Client<hyper::client::HttpConnector>,
     },
+    #[serde(rename = "query")]
+    Query {
+        routes: HashMap<String, String>,
+        #[serde(default)]
+        default: String,
+        #[serde(skip)]
+        not_found: Option<Box<StaticPage>>,
+    },
 }
 
 impl StaticPage {
@@ -148,29 +299,107 @@ impl StaticPage {
         "text/html".to_string()
     }
 
-    pub async fn serve(&self, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+    fn compute_etag(bytes: &[u8]) -> String {
+        format!("\"{:x}\"", Sha256::digest(bytes))
+    }
+
+    fn not_modified(req: &Request<Body>, etag: &str, last_modified: Option<SystemTime>) -> bool {
+        if let Some(if_none_match) = req.headers().get(IF_NONE_MATCH) {
+            return if_none_match
+                .to_str()
+                .map(|value| value.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"))
+                .unwrap_or(false);
+        }
+
+        if let (Some(last_modified), Some(if_modified_since)) = (
+            last_modified,
+            req.headers()
+                .get(IF_MODIFIED_SINCE)
+                .and_then(|h| h.to_str().ok()),
+        ) {
+            if let Ok(since) = httpdate::parse_http_date(if_modified_since) {
+                // HTTP dates have one-second resolution, so compare whole seconds.
+                let secs = |t: SystemTime| {
+                    t.duration_since(SystemTime::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0)
+                };
+                return secs(last_modified) <= secs(since);
+            }
+        }
+
+        false
+    }
+
+    pub async fn serve(
+        &self,
+        req: Request<Body>,
+        captures: Option<&CaptureSet>,
+    ) -> anyhow::Result<Response<Body>> {
         match &self {
             Self::Redirect { to } => Ok(Response::builder()
                 .status(307)
-                .header("Location", to)
+                .header("Location", Self::substitute_placeholders(to, captures)?)
                 .body(Body::empty())?),
-            Self::Embedded { data, content_type } => Ok(Response::builder()
-                .status(200)
-                .header("Content-Type", content_type)
-                .body(Bytes::copy_from_slice(data.as_slice()).into())?),
+            Self::Embedded {
+                data,
+                content_type,
+                cached_etag,
+            } => {
+                if Self::not_modified(&req, cached_etag, None) {
+                    return Ok(Response::builder()
+                        .status(304)
+                        .header("ETag", cached_etag)
+                        .body(Body::empty())?);
+                }
+
+                Ok(Response::builder()
+                    .status(200)
+                    .header(
+                        "Content-Type",
+                        Self::substitute_placeholders(content_type, captures)?,
+                    )
+                    .header("ETag", cached_etag)
+                    .header("Cache-Control", "no-cache")
+                    .body(Bytes::copy_from_slice(data.as_slice()).into())?)
+            }
             Self::StaticFile {
                 content_type,
                 cached_data,
+                cached_etag,
+                last_modified,
                 ..
-            } => Ok(Response::builder()
-                .status(200)
-                .header("Content-Type", content_type)
-                .body(Bytes::copy_from_slice(cached_data.as_slice()).into())?),
+            } => {
+                if Self::not_modified(&req, cached_etag, *last_modified) {
+                    let mut builder = Response::builder().status(304).header("ETag", cached_etag);
+                    if let Some(last_modified) = last_modified {
+                        builder = builder.header("Last-Modified", httpdate::fmt_http_date(*last_modified));
+                    }
+                    return Ok(builder.body(Body::empty())?);
+                }
+
+                let mut builder = Response::builder()
+                    .status(200)
+                    .header(
+                        "Content-Type",
+                        Self::substitute_placeholders(content_type, captures)?,
+                    )
+                    .header("ETag", cached_etag)
+                    .header("Cache-Control", "no-cache");
+                if let Some(last_modified) = last_modified {
+                    builder = builder.header("Last-Modified", httpdate::fmt_http_date(*last_modified));
+                }
+
+                Ok(builder.body(Bytes::copy_from_slice(cached_data.as_slice()).into())?)
+            }
             Self::ReverseProxy {
                 scheme,
                 host,
                 client,
             } => {
+                let scheme = Self::substitute_placeholders(scheme, captures)?;
+                let host = Self::substitute_placeholders(host, captures)?;
+
                 let mut parts = req.uri().clone().into_parts();
                 parts.scheme = Some(scheme.parse()?);
                 parts.authority = Some(host.parse()?);
@@ -188,24 +417,201 @@ impl StaticPage {
 
                 Ok(client.request(new_req).await?)
             }
+            Self::Query {
+                routes,
+                default,
+                not_found,
+            } => {
+                let decoded = Self::decode_query(req.uri().query().unwrap_or(""));
+                let mut words = decoded.split_whitespace();
+                let keyword = words.next().unwrap_or("");
+                let rest: Vec<&str> = words.collect();
+
+                let (template, query_arg, positional) = if let Some(template) = routes.get(keyword)
+                {
+                    (template.as_str(), rest.join(" "), rest)
+                } else if !default.is_empty() {
+                    (
+                        default.as_str(),
+                        decoded.clone(),
+                        decoded.split_whitespace().collect(),
+                    )
+                } else {
+                    let not_found = not_found
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("Query handler has no not_found page configured"))?;
+                    return not_found.serve(req, None).await;
+                };
+
+                let location = Self::render_template(template, &query_arg, &positional);
+
+                Ok(Response::builder()
+                    .status(307)
+                    .header("Location", location)
+                    .body(Body::empty())?)
+            }
         }
     }
+
+    fn decode_query(raw: &str) -> String {
+        percent_encoding::percent_decode_str(&raw.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned()
+    }
+
+    fn render_template(template: &str, query_arg: &str, positional: &[&str]) -> String {
+        let mut result = template.replace(
+            "{{query}}",
+            &utf8_percent_encode(query_arg, NON_ALPHANUMERIC).to_string(),
+        );
+
+        for (i, arg) in positional.iter().enumerate() {
+            result = result.replace(
+                &format!("{{{{{}}}}}", i),
+                &utf8_percent_encode(arg, NON_ALPHANUMERIC).to_string(),
+            );
+        }
+
+        result
+    }
+
+    pub fn set_not_found_page(&mut self, page: &StaticPage) {
+        if let Self::Query { not_found, .. } = self {
+            *not_found = Some(Box::new(page.clone()));
+        }
+    }
+
+    fn placeholder_names(s: &str) -> Vec<&str> {
+        let mut names = Vec::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+            match after.find("}}") {
+                Some(end) => {
+                    names.push(&after[..end]);
+                    rest = &after[end + 2..];
+                }
+                None => break,
+            }
+        }
+
+        names
+    }
+
+    fn substitute_placeholders(s: &str, captures: Option<&CaptureSet>) -> anyhow::Result<String> {
+        let mut result = String::with_capacity(s.len());
+        let mut rest = s;
+
+        loop {
+            match rest.find("{{") {
+                Some(start) => {
+                    result.push_str(&rest[..start]);
+                    let after = &rest[start + 2..];
+                    let end = after
+                        .find("}}")
+                        .ok_or_else(|| anyhow!("unterminated {{{{ placeholder in {:?}", s))?;
+                    let name = &after[..end];
+                    let value = captures
+                        .and_then(|c| c.get(name))
+                        .ok_or_else(|| anyhow!("no capture group named {:?}", name))?;
+
+                    result.push_str(value);
+                    rest = &after[end + 2..];
+                }
+                None => {
+                    result.push_str(rest);
+                    break;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn placeholder_fields(&self) -> Vec<&str> {
+        match self {
+            Self::Redirect { to } => vec![to.as_str()],
+            Self::Embedded { content_type, .. } => vec![content_type.as_str()],
+            Self::StaticFile { content_type, .. } => vec![content_type.as_str()],
+            Self::ReverseProxy { scheme, host, .. } => vec![scheme.as_str(), host.as_str()],
+            Self::Query { .. } => vec![],
+        }
+    }
+
+    fn validate_placeholders(&self, matcher: &Matcher) -> anyhow::Result<()> {
+        let group_info = matcher.regex_group_info();
+
+        for field in self.placeholder_fields() {
+            for name in Self::placeholder_names(field) {
+                let valid = match &group_info {
+                    Some((count, names)) => match name.parse::<usize>() {
+                        Ok(index) => index < *count,
+                        Err(_) => names.iter().any(|n| n == name),
+                    },
+                    None => false,
+                };
+
+                if !valid {
+                    return Err(anyhow!(
+                        "placeholder {{{{{}}}}} has no matching capture group in the sibling matcher",
+                        name
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl CheckConfig for StaticPage {
     fn check(&mut self) -> anyhow::Result<()> {
-        if let StaticPage::StaticFile {
-            path, cached_data, ..
-        } = self
-        {
-            if let Err(e) = File::open(path).and_then(|mut x| x.read_to_end(cached_data)) {
-                return Err(anyhow!(e).context("inside a StaticFile page"));
+        match self {
+            StaticPage::StaticFile {
+                path,
+                cached_data,
+                cached_etag,
+                last_modified,
+                ..
+            } => {
+                let mut file =
+                    File::open(path).map_err(|e| anyhow!(e).context("inside a StaticFile page"))?;
+                file.read_to_end(cached_data)
+                    .map_err(|e| anyhow!(e).context("inside a StaticFile page"))?;
+
+                *cached_etag = Self::compute_etag(cached_data);
+                *last_modified = file.metadata().ok().and_then(|m| m.modified().ok());
+            }
+            StaticPage::Embedded {
+                data, cached_etag, ..
+            } => {
+                *cached_etag = Self::compute_etag(data);
+            }
+            StaticPage::Query { routes, default, .. } => {
+                for template in routes.values().chain((!default.is_empty()).then_some(&*default))
+                {
+                    Self::check_template(template)?;
+                }
             }
+            StaticPage::Redirect { .. } | StaticPage::ReverseProxy { .. } => {}
         };
         Ok(())
     }
 }
 
+impl StaticPage {
+    fn check_template(template: &str) -> anyhow::Result<()> {
+        let placeholder = regex::Regex::new(r"\{\{[^}]*\}\}").unwrap();
+        let dummy = placeholder.replace_all(template, "x");
+
+        dummy
+            .parse::<Uri>()
+            .map(|_| ())
+            .map_err(|e| anyhow!(e).context(format!("template {:?} is not a valid URI", template)))
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Handler {
     #[serde(rename = "must_match")]
@@ -218,6 +624,9 @@ impl CheckConfig for Handler {
     fn check(&mut self) -> anyhow::Result<()> {
         self.matcher.check().context("inside the root matcher")?;
         self.page.check().context("inside the page")?;
+        self.page
+            .validate_placeholders(&self.matcher)
+            .context("inside the page")?;
         Ok(())
     }
 }
@@ -228,10 +637,16 @@ pub struct HostSpec {
     pub host: String,
     #[serde(default = "HostSpec::default_port")]
     pub port: u16,
+    #[serde(default)]
+    pub tls: Option<TlsSpec>,
 }
 
 impl CheckConfig for HostSpec {
     fn check(&mut self) -> anyhow::Result<()> {
+        if let Some(ref mut tls) = self.tls {
+            tls.check().context("inside the tls block")?;
+        }
+
         Ok(())
     }
 }
@@ -241,6 +656,7 @@ impl Default for HostSpec {
         Self {
             host: Self::default_host(),
             port: Self::default_port(),
+            tls: None,
         }
     }
 }
@@ -257,6 +673,14 @@ impl HostSpec {
     pub fn spec(&self) -> SocketAddr {
         SocketAddr::new(self.host.parse().unwrap(), self.port)
     }
+
+    pub fn scheme(&self) -> &'static str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -288,10 +712,24 @@ impl ErrorPages {
         StaticPage::Embedded {
             data: "404: not found.".as_bytes().to_vec(),
             content_type: "text/html".to_string(),
+            cached_etag: String::new(),
         }
     }
 }
 
+#[derive(Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct TimeoutsSpec {
+    pub header_read: Option<u64>,
+    pub keep_alive: Option<u64>,
+    pub request_body: Option<u64>,
+}
+
+impl CheckConfig for TimeoutsSpec {
+    fn check(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Config {
     #[serde(flatten, default)]
@@ -299,13 +737,17 @@ pub struct Config {
     pub handlers: Vec<Handler>,
     #[serde(default)]
     pub errors: ErrorPages,
+    #[serde(default)]
+    pub timeouts: TimeoutsSpec,
 }
 
 impl CheckConfig for Config {
     fn check(&mut self) -> anyhow::Result<()> {
         self.host.check().context("inside the HostSpec")?;
         self.errors.check().context("inside the error handlers")?;
+        self.timeouts.check().context("inside the timeouts")?;
         for (i, handler) in self.handlers.iter_mut().enumerate() {
+            handler.page.set_not_found_page(&self.errors.not_found);
             handler
                 .check()
                 .context(format!("inside handler {} (counting from 0)", i))?;
@@ -317,7 +759,8 @@ impl CheckConfig for Config {
 
 impl Config {
     pub fn requires_restart<T: Borrow<Self>>(&self, other: T) -> bool {
-        self.host != other.borrow().host
+        let other = other.borrow();
+        self.host != other.host || self.timeouts != other.timeouts
     }
 
     #[cfg(test)]
@@ -325,6 +768,7 @@ impl Config {
         HostSpec {
             host: "127.0.0.1".to_string(),
             port: 43982, // a random port unlikely to be taken
+            tls: None,
         }
     }
 
@@ -340,6 +784,7 @@ impl Config {
                     page: StaticPage::Embedded {
                         data: "abc".as_bytes().to_vec(),
                         content_type: "text/plain".to_string(),
+                        cached_etag: String::new(),
                     },
                 },
                 Handler {
@@ -352,6 +797,187 @@ impl Config {
                 },
             ],
             errors: ErrorPages::default(),
+            timeouts: TimeoutsSpec::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
         }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn method_matcher_is_case_insensitive() {
+        let matcher = Matcher::Method {
+            methods: vec!["post".to_string()],
+        };
+        assert!(matcher.matches(&request("POST", "/", &[])));
+        assert!(!matcher.matches(&request("GET", "/", &[])));
+    }
+
+    #[test]
+    fn header_matcher_checks_presence_value_and_regex() {
+        let mut presence = Matcher::Header {
+            name: "x-flag".to_string(),
+            value: None,
+            regex: None,
+            compiled: None,
+        };
+        presence.check().unwrap();
+        assert!(presence.matches(&request("GET", "/", &[("x-flag", "anything")])));
+        assert!(!presence.matches(&request("GET", "/", &[])));
+
+        let mut exact = Matcher::Header {
+            name: "x-flag".to_string(),
+            value: Some("yes".to_string()),
+            regex: None,
+            compiled: None,
+        };
+        exact.check().unwrap();
+        assert!(exact.matches(&request("GET", "/", &[("x-flag", "yes")])));
+        assert!(!exact.matches(&request("GET", "/", &[("x-flag", "no")])));
+
+        let mut by_regex = Matcher::Header {
+            name: "x-flag".to_string(),
+            value: None,
+            regex: Some("^a+$".to_string()),
+            compiled: None,
+        };
+        by_regex.check().unwrap();
+        assert!(by_regex.matches(&request("GET", "/", &[("x-flag", "aaa")])));
+        assert!(!by_regex.matches(&request("GET", "/", &[("x-flag", "bbb")])));
+    }
+
+    #[test]
+    fn host_matcher_is_case_insensitive() {
+        let matcher = Matcher::Host {
+            host: "Example.com".to_string(),
+        };
+        assert!(matcher.matches(&request("GET", "/", &[("host", "example.COM")])));
+        assert!(!matcher.matches(&request("GET", "/", &[("host", "other.com")])));
+    }
+
+    #[test]
+    fn host_matcher_ignores_the_port() {
+        let matcher = Matcher::Host {
+            host: "example.com".to_string(),
+        };
+        assert!(matcher.matches(&request("GET", "/", &[("host", "example.com:8387")])));
+    }
+
+    #[test]
+    fn host_matcher_handles_ipv6_literals() {
+        let matcher = Matcher::Host {
+            host: "[::1]".to_string(),
+        };
+        assert!(matcher.matches(&request("GET", "/", &[("host", "[::1]:8387")])));
+        assert!(matcher.matches(&request("GET", "/", &[("host", "[::1]")])));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let etag = "\"abc\"";
+        let last_modified = SystemTime::now();
+        let stale_since = "Mon, 01 Jan 1990 00:00:00 GMT";
+
+        // If-None-Match matches: 304, even though If-Modified-Since is stale.
+        let req = request(
+            "GET",
+            "/",
+            &[("If-None-Match", etag), ("If-Modified-Since", stale_since)],
+        );
+        assert!(StaticPage::not_modified(&req, etag, Some(last_modified)));
+
+        // If-None-Match present but doesn't match: not a 304, even though
+        // If-Modified-Since alone would have allowed it.
+        let fresh_since = httpdate::fmt_http_date(last_modified);
+        let req = request(
+            "GET",
+            "/",
+            &[("If-None-Match", "\"other\""), ("If-Modified-Since", &fresh_since)],
+        );
+        assert!(!StaticPage::not_modified(&req, etag, Some(last_modified)));
+
+        // No If-None-Match: falls back to If-Modified-Since.
+        let req = request("GET", "/", &[("If-Modified-Since", &fresh_since)]);
+        assert!(StaticPage::not_modified(&req, etag, Some(last_modified)));
+    }
+
+    #[test]
+    fn query_decodes_plus_and_percent_encoding() {
+        assert_eq!(StaticPage::decode_query("hello+world%21"), "hello world!");
+    }
+
+    #[test]
+    fn query_template_renders_and_percent_encodes_arguments() {
+        let rendered = StaticPage::render_template(
+            "https://example.com/search?q={{query}}&first={{0}}",
+            "hello world's",
+            &["hello", "world's"],
+        );
+        assert_eq!(
+            rendered,
+            "https://example.com/search?q=hello%20world%27s&first=hello"
+        );
+    }
+
+    #[test]
+    fn placeholders_substitute_named_and_numbered_capture_groups() {
+        let mut matcher = Matcher::Regex {
+            expr: r"^/u/(?P<id>\w+)$".to_string(),
+            compiled: None,
+        };
+        matcher.check().unwrap();
+
+        let req = request("GET", "/u/42", &[]);
+        let captures = matcher.captures(&req).unwrap();
+
+        let rendered =
+            StaticPage::substitute_placeholders("https://example.com/users/{{id}}", Some(&captures))
+                .unwrap();
+        assert_eq!(rendered, "https://example.com/users/42");
+    }
+
+    #[test]
+    fn substitute_placeholders_rejects_unknown_capture_group() {
+        assert!(StaticPage::substitute_placeholders("{{missing}}", None).is_err());
+    }
+
+    #[test]
+    fn validate_placeholders_rejects_unknown_capture_group() {
+        let mut matcher = Matcher::Regex {
+            expr: r"^/u/(?P<id>\w+)$".to_string(),
+            compiled: None,
+        };
+        matcher.check().unwrap();
+
+        let page = StaticPage::Redirect {
+            to: "https://example.com/users/{{missing}}".to_string(),
+        };
+
+        assert!(page.validate_placeholders(&matcher).is_err());
+    }
+
+    #[test]
+    fn validate_placeholders_accepts_known_capture_group() {
+        let mut matcher = Matcher::Regex {
+            expr: r"^/u/(?P<id>\w+)$".to_string(),
+            compiled: None,
+        };
+        matcher.check().unwrap();
+
+        let page = StaticPage::Redirect {
+            to: "https://example.com/users/{{id}}".to_string(),
+        };
+
+        assert!(page.validate_placeholders(&matcher).is_ok());
     }
 }
@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 mod app;
 mod config;
+mod tls;
 
 #[tokio::main]
 async fn main() {
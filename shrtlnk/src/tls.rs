@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use hyper::server::accept::Accept;
+use serde::Deserialize;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite},
+    sync::RwLock as AsyncRwLock,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        server::{ClientHello, ResolvesServerCert},
+        sign::{any_supported_type, CertifiedKey},
+        Certificate, PrivateKey, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+use crate::config::CheckConfig;
+
+#[derive(Deserialize, PartialEq, Eq, Clone)]
+#[serde(tag = "source")]
+pub enum TlsSpec {
+    #[serde(rename = "manual")]
+    Manual { cert_file: String, key_file: String },
+    #[serde(rename = "acme")]
+    Acme {
+        domain: String,
+        contact: String,
+        #[serde(default = "TlsSpec::default_directory")]
+        directory: String,
+        cache_dir: String,
+    },
+}
+
+impl TlsSpec {
+    fn default_directory() -> String {
+        "https://acme-v02.api.letsencrypt.org/directory".to_string()
+    }
+}
+
+impl CheckConfig for TlsSpec {
+    fn check(&mut self) -> anyhow::Result<()> {
+        match self {
+            Self::Manual { cert_file, key_file } => {
+                if !Path::new(cert_file).exists() {
+                    return Err(anyhow!("cert_file {} does not exist", cert_file));
+                }
+                if !Path::new(key_file).exists() {
+                    return Err(anyhow!("key_file {} does not exist", key_file));
+                }
+                Ok(())
+            }
+            Self::Acme { cache_dir, .. } => {
+                std::fs::create_dir_all(cache_dir)
+                    .context("creating the ACME cache directory")?;
+                Ok(())
+            }
+        }
+    }
+}
+
+// std::sync::RwLock, not tokio's: resolve() is a sync callback from rustls.
+struct CertResolver {
+    current: std::sync::RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+pub struct CertManager {
+    spec: TlsSpec,
+    resolver: Arc<CertResolver>,
+    challenges: AsyncRwLock<HashMap<String, String>>,
+}
+
+impl CertManager {
+    pub fn new(spec: TlsSpec) -> Arc<Self> {
+        Arc::new(Self {
+            spec,
+            resolver: Arc::new(CertResolver {
+                current: std::sync::RwLock::new(None),
+            }),
+            challenges: AsyncRwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn server_config(&self) -> ServerConfig {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        config
+    }
+
+    fn domain(&self) -> &str {
+        match &self.spec {
+            TlsSpec::Acme { domain, .. } => domain.as_str(),
+            TlsSpec::Manual { .. } => unreachable!("CertManager is only used for ACME specs"),
+        }
+    }
+
+    fn cache_paths(&self) -> (PathBuf, PathBuf) {
+        let TlsSpec::Acme { cache_dir, domain, .. } = &self.spec else {
+            unreachable!("CertManager is only used for ACME specs")
+        };
+        (
+            Path::new(cache_dir).join(format!("{}.cert.pem", domain)),
+            Path::new(cache_dir).join(format!("{}.key.pem", domain)),
+        )
+    }
+
+    pub async fn challenge_response(&self, token: &str) -> Option<String> {
+        self.challenges.read().await.get(token).cloned()
+    }
+
+    pub async fn sync_cert(&self, domain: &str) -> Result<()> {
+        let (cert_path, key_path) = self.cache_paths();
+
+        if let Some(key) = Self::load_cached(&cert_path, &key_path).await {
+            if !Self::expires_soon(&key) {
+                *self.resolver.current.write().unwrap() = Some(Arc::new(key));
+                return Ok(());
+            }
+        }
+
+        let TlsSpec::Acme { contact, directory, .. } = &self.spec else {
+            unreachable!("CertManager is only used for ACME specs")
+        };
+
+        let (cert_pem, key_pem) = self
+            .order_certificate(domain, contact, directory)
+            .await
+            .context("requesting a certificate from the ACME server")?;
+
+        fs::write(&cert_path, &cert_pem).await?;
+        fs::write(&key_path, &key_pem).await?;
+
+        let key = Self::parse_cert(cert_pem.as_bytes(), key_pem.as_bytes())?;
+        *self.resolver.current.write().unwrap() = Some(Arc::new(key));
+
+        Ok(())
+    }
+
+    async fn load_cached(cert_path: &Path, key_path: &Path) -> Option<CertifiedKey> {
+        let cert_pem = fs::read(cert_path).await.ok()?;
+        let key_pem = fs::read(key_path).await.ok()?;
+        Self::parse_cert(&cert_pem, &key_pem).ok()
+    }
+
+    fn parse_cert(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey> {
+        let certs: Vec<Certificate> = rustls_pemfile::certs(&mut &cert_pem[..])?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut &key_pem[..])?
+            .into_iter()
+            .next()
+            .map(PrivateKey)
+            .ok_or_else(|| anyhow!("no private key found in PEM data"))?;
+
+        let signing_key = any_supported_type(&key).map_err(|e| anyhow!(e))?;
+        Ok(CertifiedKey::new(certs, signing_key))
+    }
+
+    fn expires_soon(key: &CertifiedKey) -> bool {
+        const RENEWAL_WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+        let cert = match key.cert.first() {
+            Some(cert) => cert,
+            None => return true,
+        };
+        let (_, parsed) = match x509_parser::parse_x509_certificate(cert.as_ref()) {
+            Ok(pair) => pair,
+            Err(_) => return true,
+        };
+        let not_after = parsed.validity().not_after.timestamp();
+        let deadline = not_after - RENEWAL_WINDOW.as_secs() as i64;
+        time::OffsetDateTime::now_utc().unix_timestamp() >= deadline
+    }
+
+    async fn order_certificate(
+        &self,
+        domain: &str,
+        contact: &str,
+        directory: &str,
+    ) -> Result<(String, String)> {
+        use instant_acme::{
+            Account, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder,
+            OrderStatus,
+        };
+
+        let (account, _credentials) = Account::create(
+            &NewAccount {
+                contact: &[&format!("mailto:{}", contact)],
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            directory,
+            None,
+        )
+        .await?;
+
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &[Identifier::Dns(domain.to_string())],
+            })
+            .await?;
+
+        let authorizations = order.authorizations().await?;
+        for authz in &authorizations {
+            if authz.status != AuthorizationStatus::Pending {
+                continue;
+            }
+
+            let challenge = authz
+                .challenges
+                .iter()
+                .find(|c| c.kind == ChallengeType::Http01)
+                .ok_or_else(|| anyhow!("ACME server offered no HTTP-01 challenge"))?;
+
+            let key_auth = order.key_authorization(challenge).as_str().to_string();
+            self.challenges
+                .write()
+                .await
+                .insert(challenge.token.clone(), key_auth);
+
+            order.set_challenge_ready(&challenge.url).await?;
+        }
+
+        // Poll until the order is ready (or fails), then finalize and download.
+        loop {
+            let state = order.refresh().await?;
+            match state.status {
+                OrderStatus::Ready | OrderStatus::Valid => break,
+                OrderStatus::Invalid => return Err(anyhow!("ACME order became invalid")),
+                _ => tokio::time::sleep(Duration::from_secs(2)).await,
+            }
+        }
+
+        let private_key_pem = order.finalize().await?;
+        let cert_chain_pem = loop {
+            match order.certificate().await? {
+                Some(cert) => break cert,
+                None => tokio::time::sleep(Duration::from_secs(2)).await,
+            }
+        };
+
+        self.challenges.write().await.clear();
+
+        Ok((cert_chain_pem, private_key_pem))
+    }
+
+    pub fn spawn_renewal_timer(me: Arc<Self>) {
+        const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+        let domain = me.domain().to_string();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+                if let Err(e) = me.sync_cert(&domain).await {
+                    eprintln!("Failed to renew TLS certificate for {}: {}", domain, e);
+                }
+            }
+        });
+    }
+}
+
+// Generic over the inner acceptor so it can sit directly on top of an
+// `AddrIncoming`, or on top of another `Accept` wrapper (e.g. an
+// idle-timeout layer) without needing to know about it.
+pub struct TlsHyperAcceptor<A: Accept> {
+    incoming: A,
+    acceptor: TlsAcceptor,
+    handshakes:
+        Vec<Pin<Box<dyn Future<Output = std::io::Result<tokio_rustls::server::TlsStream<A::Conn>>> + Send>>>,
+}
+
+impl<A> TlsHyperAcceptor<A>
+where
+    A: Accept<Error = std::io::Error>,
+    A::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(incoming: A, server_config: Arc<ServerConfig>) -> Self {
+        Self {
+            incoming,
+            acceptor: TlsAcceptor::from(server_config),
+            handshakes: Vec::new(),
+        }
+    }
+}
+
+impl<A> Accept for TlsHyperAcceptor<A>
+where
+    A: Accept<Error = std::io::Error> + Unpin,
+    A::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Conn = tokio_rustls::server::TlsStream<A::Conn>;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Self::Conn, Self::Error>>> {
+        loop {
+            match Pin::new(&mut self.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => {
+                    let fut = self.acceptor.accept(stream);
+                    self.handshakes.push(Box::pin(fut));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => break,
+                Poll::Pending => break,
+            }
+        }
+
+        let mut i = 0;
+        while i < self.handshakes.len() {
+            match self.handshakes[i].as_mut().poll(cx) {
+                Poll::Ready(result) => {
+                    self.handshakes.remove(i);
+                    return Poll::Ready(Some(result));
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+
+        Poll::Pending
+    }
+}